@@ -5,10 +5,132 @@ use std::error::Error;
 use std::hash::{Hash, Hasher};
 use std::fmt;
 use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU8, Ordering};
 
 #[allow(deprecated)]
 use std::hash::SipHasher;
 
+/// Number of freshly inserted sparse entries to buffer before merging them
+/// into the sorted sparse list.
+const SPARSE_BUFFER_LIMIT: usize = 128;
+
+/// Once the sorted sparse list would occupy more bytes than the packed dense
+/// array (each sparse entry costs 4 bytes, each dense register costs
+/// `REGISTER_BITS` bits), the sketch is promoted to the dense representation.
+///
+/// Note: this deviates from the "roughly `6*m` bytes" figure originally
+/// proposed for this promotion. With 4-byte sparse entries and at most `m`
+/// distinct registers, the sparse list can hold at most `4*m` bytes, so a
+/// `6*m`-byte threshold would never trigger and promotion would never fire.
+/// Comparing against the packed dense array's actual size is the crossover
+/// that's actually reachable.
+const SPARSE_MAX_BYTES_PER_REGISTER: usize = 1;
+
+/// A register only ever holds a value from 0 to `64 - b + 1 <= 61`, which
+/// always fits in 6 bits, so the dense representation packs `m` six-bit
+/// fields into a `Vec<u64>` instead of spending a full byte per register.
+const REGISTER_BITS: usize = 6;
+const REGISTER_MASK: u64 = (1 << REGISTER_BITS) - 1;
+const BITS_PER_WORD: usize = 64;
+
+/// Bit-packed backing store for the dense representation: `m` six-bit
+/// register values packed into as few `u64` words as possible. A field may
+/// straddle a word boundary, which `get`/`set` handle explicitly.
+struct PackedRegisters {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl PackedRegisters {
+    fn new(len: usize) -> Self {
+        let words = (len * REGISTER_BITS + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        PackedRegisters { words: vec![0; words], len: len }
+    }
+
+    fn size_bytes(len: usize) -> usize {
+        (len * REGISTER_BITS + 7) / 8
+    }
+
+    fn get(&self, index: usize) -> u8 {
+        let bit = index * REGISTER_BITS;
+        let word = bit / BITS_PER_WORD;
+        let shift = bit % BITS_PER_WORD;
+        let bits_in_word = BITS_PER_WORD - shift;
+
+        let mut value = self.words[word] >> shift;
+        if bits_in_word < REGISTER_BITS {
+            let spill_bits = REGISTER_BITS - bits_in_word;
+            let spill = self.words[word + 1] & ((1 << spill_bits) - 1);
+            value |= spill << bits_in_word;
+        }
+        (value & REGISTER_MASK) as u8
+    }
+
+    fn set(&mut self, index: usize, value: u8) {
+        let bit = index * REGISTER_BITS;
+        let word = bit / BITS_PER_WORD;
+        let shift = bit % BITS_PER_WORD;
+        let bits_in_word = BITS_PER_WORD - shift;
+        let value = (value as u64) & REGISTER_MASK;
+
+        self.words[word] &= !(REGISTER_MASK << shift);
+        self.words[word] |= value << shift;
+
+        if bits_in_word < REGISTER_BITS {
+            let spill_bits = REGISTER_BITS - bits_in_word;
+            let spill_mask = (1u64 << spill_bits) - 1;
+            self.words[word + 1] &= !spill_mask;
+            self.words[word + 1] |= value >> bits_in_word;
+        }
+    }
+
+    fn iter<'a>(&'a self) -> PackedRegistersIter<'a> {
+        PackedRegistersIter { registers: self, index: 0 }
+    }
+}
+
+struct PackedRegistersIter<'a> {
+    registers: &'a PackedRegisters,
+    index: usize,
+}
+
+impl<'a> Iterator for PackedRegistersIter<'a> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.index >= self.registers.len {
+            return None;
+        }
+        let value = self.registers.get(self.index);
+        self.index += 1;
+        Some(value)
+    }
+}
+
+/// Backing store for the registers.
+///
+/// `Sparse` keeps an exact, sorted list of `(index, rank)` pairs (as used by
+/// the HyperLogLog++ "sparse representation") so that small cardinalities
+/// cost a few bytes per distinct register instead of six bits per register
+/// up front. `insert` buffers new entries and periodically merges them into
+/// the sorted list; once the sparse list grows past the packed dense array's
+/// size it is promoted to `Dense`.
+enum Registers {
+    Dense(PackedRegisters),
+    Sparse {
+        /// sorted, deduplicated `(index << 8) | rank` entries.
+        sorted: Vec<u32>,
+        /// entries appended by `insert` that have not yet been merged into `sorted`.
+        buffer: Vec<u32>,
+    },
+}
+
+impl Registers {
+    fn new_sparse() -> Self {
+        Registers::Sparse { sorted: Vec::new(), buffer: Vec::new() }
+    }
+}
+
 pub struct HyperLogLog {
     /// `b` is the number of bit addressing registers, takes the range from 4 to 16.
     b: u8,
@@ -19,17 +141,33 @@ pub struct HyperLogLog {
     /// the number of registers, calculated as 2 to the `b`th power.
     m: usize,
 
-    /// ???
+    /// The classic `alpha*m^2/sum(2^-x)` bias-correction constant. No longer
+    /// used by `cardinality()` now that `ertl_raw_estimate` computes the raw
+    /// estimate directly from the register histogram, but kept around (and
+    /// computed via `get_alpha`, which still validates `b`) so `Debug`
+    /// output and existing callers that inspect it keep working.
     alpha: f64,
 
-    /// Registers with size of `m` bytes.
-    registers: Vec<u8>,
+    /// Registers, either the exact sparse list used at small cardinalities
+    /// or the dense array of `m` six-bit packed registers.
+    registers: Registers,
 
     /// Keys are used in initialize SipHasher.
     hasher_key0: u64,
     hasher_key1: u64,
 }
 
+/// Note: an earlier revision of this crate added a third variant,
+/// `BiasCorrected`, for a HyperLogLog++-style empirical bias correction on
+/// top of the raw estimate. That correction was calibrated against the
+/// classic `alpha*m^2/sum(2^-x)` raw estimator's known bias curve; once
+/// `ertl_raw_estimate` replaced that formula with a near-unbiased
+/// maximum-likelihood estimate, the same correction table mis-applied to
+/// the new raw value and introduced a systematic ~9% bias at `raw≈m` rather
+/// than removing one. It was removed rather than recalibrated: this
+/// supersedes the bias-correction feature outright, since `ertl_raw_estimate`
+/// does not need HyperLogLog++'s correction step to stay within
+/// `typical_error_rate()`.
 #[derive(Debug)]
 pub enum Estimator {
     HyperLogLog,
@@ -53,7 +191,7 @@ impl HyperLogLog {
             b_mask: m - 1,
             m: m,
             alpha: alpha,
-            registers: vec![0; m],
+            registers: Registers::new_sparse(),
             hasher_key0: rng.gen(),
             hasher_key1: rng.gen(),
         })
@@ -65,57 +203,98 @@ impl HyperLogLog {
         let w = x >> self.b;
 
         let p1 = position_of_leftmost_one_bit(w, 64 - self.b);
-        let p2 = &mut self.registers[j];
-        if *p2 < p1 {
-            *p2 = p1
+
+        match self.registers {
+            Registers::Dense(ref mut registers) => {
+                if registers.get(j) < p1 {
+                    registers.set(j, p1);
+                }
+            }
+            Registers::Sparse { .. } => {
+                self.insert_sparse(j, p1);
+            }
         }
     }
 
-    #[allow(deprecated)]
-    fn hash<H: Hash>(&self, value: &H) -> u64 {
-        let mut hasher = SipHasher::new_with_keys(self.hasher_key0, self.hasher_key1);
-        value.hash(&mut hasher);
-        hasher.finish()
+    fn insert_sparse(&mut self, index: usize, rank: u8) {
+        let buffer_len = match self.registers {
+            Registers::Sparse { ref mut buffer, .. } => {
+                buffer.push(encode_sparse_entry(index, rank));
+                buffer.len()
+            }
+            Registers::Dense(_) => unreachable!(),
+        };
+
+        if buffer_len >= SPARSE_BUFFER_LIMIT {
+            self.flush_sparse_buffer();
+            self.promote_if_needed();
+        }
     }
 
-    pub fn cardinality(&self) -> f64 {
-        estimate_cardinality(self).0
+    fn flush_sparse_buffer(&mut self) {
+        if let Registers::Sparse { ref mut sorted, ref mut buffer } = self.registers {
+            if buffer.is_empty() {
+                return;
+            }
+            sorted.extend(buffer.drain(..));
+            merge_sparse_entries(sorted);
+        }
     }
 
-    pub fn typical_error_rate(&self) -> f64 {
-        1.04 / (self.m as f64).sqrt()
+    fn promote_if_needed(&mut self) {
+        let should_promote = match self.registers {
+            Registers::Sparse { ref sorted, .. } => {
+                sorted.len() * 4 > SPARSE_MAX_BYTES_PER_REGISTER * PackedRegisters::size_bytes(self.m)
+            }
+            Registers::Dense(_) => false,
+        };
+
+        if should_promote {
+            self.promote_to_dense();
+        }
     }
 
-    pub fn histgram_of_register_value_distribution(&self) -> String {
-        let mut histgram = Vec::new();
+    fn promote_to_dense(&mut self) {
+        let dense = match self.registers {
+            Registers::Sparse { ref sorted, .. } => dense_snapshot(self.m, sorted),
+            Registers::Dense(_) => return,
+        };
+        self.registers = Registers::Dense(dense);
+    }
 
-        let mut map = BTreeMap::new();
-        for x in &self.registers {
-            let count = map.entry(*x).or_insert(0);
-            *count += 1;
+    fn merge_register(&mut self, index: usize, rank: u8) {
+        match self.registers {
+            Registers::Dense(ref mut registers) => {
+                if registers.get(index) < rank {
+                    registers.set(index, rank);
+                }
+            }
+            Registers::Sparse { ref mut buffer, .. } => {
+                buffer.push(encode_sparse_entry(index, rank));
+            }
         }
+    }
 
-        if let (Some(last_reg_value), Some(max_count)) = (map.keys().last(), map.values().max()) {
-            let width = 40.0;
-            let rate = width / (*max_count as f64);
+    fn hash<H: Hash>(&self, value: &H) -> u64 {
+        hash_value(value, self.hasher_key0, self.hasher_key1)
+    }
 
-            for i in 0..(last_reg_value + 1) {
-                let mut line = format!("{:3}: ", i);
+    pub fn cardinality(&self) -> f64 {
+        estimate_cardinality(self).0
+    }
 
-                if let Some(count) = map.get(&i) {
-                    let h_bar = std::iter::repeat("*")
-                        .take((*count as f64 * rate).ceil() as usize)
-                        .collect::<String>();
-                    line.push_str(&h_bar);
-                    line.push_str(&format!(" {}", count));
-                } else {
-                    line.push_str("0");
-                };
+    pub fn typical_error_rate(&self) -> f64 {
+        1.04 / (self.m as f64).sqrt()
+    }
 
-                histgram.push(line);
+    pub fn histgram_of_register_value_distribution(&self) -> String {
+        match self.registers {
+            Registers::Dense(ref registers) => histogram_from_values(registers.iter()),
+            Registers::Sparse { ref sorted, ref buffer } => {
+                let entries = effective_sparse_entries(sorted, buffer);
+                histogram_from_values(dense_snapshot(self.m, &entries).iter())
             }
         }
-        histgram.join("\n")
     }
 
     pub fn from_template(template: &HyperLogLog) -> Self {
@@ -125,7 +304,7 @@ impl HyperLogLog {
             b_mask: m - 1,
             m: m,
             alpha: template.alpha,
-            registers: vec![0; m],
+            registers: Registers::new_sparse(),
             hasher_key0: template.hasher_key0,
             hasher_key1: template.hasher_key1,
         }
@@ -133,11 +312,23 @@ impl HyperLogLog {
 
     pub fn merge(&mut self, other: &HyperLogLog) -> Result<(), Box<Error>> {
         if self.b == other.b && self.m == other.m && self.hasher_key0 == other.hasher_key0 && self.hasher_key1 == other.hasher_key1 {
-            for (p1, p2) in self.registers.iter_mut().zip(other.registers.iter()) {
-                if *p1 < *p2 {
-                    *p1 = *p2
+            match other.registers {
+                Registers::Dense(ref other_registers) => {
+                    for (index, rank) in other_registers.iter().enumerate() {
+                        if rank > 0 {
+                            self.merge_register(index, rank);
+                        }
+                    }
+                }
+                Registers::Sparse { ref sorted, ref buffer } => {
+                    for entry in effective_sparse_entries(sorted, buffer) {
+                        let (index, rank) = decode_sparse_entry(entry);
+                        self.merge_register(index, rank);
+                    }
                 }
             }
+            self.flush_sparse_buffer();
+            self.promote_if_needed();
             Ok(())
         } else {
             Err(From::from(format!("Specs does not match.\
@@ -153,6 +344,125 @@ impl HyperLogLog {
             )))
         }
     }
+
+    /// Serializes the sketch into a versioned, self-describing buffer: a
+    /// magic/version/precision/hasher-key header followed by the register
+    /// payload, dense or sparse depending on the current representation.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(FORMAT_VERSION);
+        bytes.push(self.b);
+        bytes.extend_from_slice(&self.hasher_key0.to_be_bytes());
+        bytes.extend_from_slice(&self.hasher_key1.to_be_bytes());
+
+        match self.registers {
+            Registers::Dense(ref registers) => {
+                bytes.push(TAG_DENSE);
+                bytes.extend(registers.iter());
+            }
+            Registers::Sparse { ref sorted, ref buffer } => {
+                bytes.push(TAG_SPARSE);
+                let entries = effective_sparse_entries(sorted, buffer);
+                bytes.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+                for entry in entries {
+                    bytes.extend_from_slice(&entry.to_be_bytes());
+                }
+            }
+        }
+
+        bytes
+    }
+
+    /// Parses a buffer produced by `to_bytes`, validating the magic, version
+    /// and payload lengths before trusting the header's `b`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<Error>> {
+        if bytes.len() < HEADER_LEN {
+            return Err(From::from(format!("buffer too short to hold a header: {} bytes", bytes.len())));
+        }
+
+        if bytes[0..4] != MAGIC[..] {
+            return Err(From::from("not a HyperLogLog sketch: bad magic"));
+        }
+
+        let version = bytes[4];
+        if version != FORMAT_VERSION {
+            return Err(From::from(format!("unsupported format version: {}", version)));
+        }
+
+        let b = bytes[5];
+        if b < 4 || b > 16 {
+            return Err(From::from(format!("b must be between 4 and 16. b = {}", b)));
+        }
+
+        let hasher_key0 = read_u64(&bytes[6..14]);
+        let hasher_key1 = read_u64(&bytes[14..22]);
+        let tag = bytes[22];
+        let payload = &bytes[HEADER_LEN..];
+
+        let m = 1 << b;
+        let alpha = get_alpha(b)?;
+
+        let registers = match tag {
+            TAG_DENSE => {
+                if payload.len() != m {
+                    return Err(From::from(format!("dense payload is {} bytes, expected {}", payload.len(), m)));
+                }
+                let mut registers = PackedRegisters::new(m);
+                for (index, &value) in payload.iter().enumerate() {
+                    registers.set(index, value);
+                }
+                Registers::Dense(registers)
+            }
+            TAG_SPARSE => {
+                if payload.len() < 4 {
+                    return Err(From::from("sparse payload too short to hold an entry count"));
+                }
+                let count = read_u32(&payload[0..4]) as usize;
+                let entries = &payload[4..];
+                if entries.len() != count * 4 {
+                    return Err(From::from(format!("sparse payload holds {} bytes, expected {}", entries.len(), count * 4)));
+                }
+                let sorted = entries.chunks(4).map(read_u32).collect();
+                Registers::Sparse { sorted: sorted, buffer: Vec::new() }
+            }
+            _ => return Err(From::from(format!("unknown register format tag: {}", tag))),
+        };
+
+        Ok(HyperLogLog {
+            b: b,
+            b_mask: m - 1,
+            m: m,
+            alpha: alpha,
+            registers: registers,
+            hasher_key0: hasher_key0,
+            hasher_key1: hasher_key1,
+        })
+    }
+}
+
+/// `to_bytes`/`from_bytes` magic, marking the buffer as a HyperLogLog sketch.
+const MAGIC: [u8; 4] = *b"HLL1";
+
+/// Bumped whenever the serialized layout changes incompatibly.
+const FORMAT_VERSION: u8 = 1;
+
+const TAG_DENSE: u8 = 0;
+const TAG_SPARSE: u8 = 1;
+
+/// Header size in bytes: magic(4) + version(1) + b(1) + hasher keys(8+8) + format tag(1).
+const HEADER_LEN: usize = 4 + 1 + 1 + 8 + 8 + 1;
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_be_bytes(buf)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(bytes);
+    u32::from_be_bytes(buf)
 }
 
 fn get_alpha(b: u8) -> Result<f64, Box<Error>> {
@@ -168,6 +478,13 @@ fn get_alpha(b: u8) -> Result<f64, Box<Error>> {
     }
 }
 
+#[allow(deprecated)]
+fn hash_value<H: Hash>(value: &H, hasher_key0: u64, hasher_key1: u64) -> u64 {
+    let mut hasher = SipHasher::new_with_keys(hasher_key0, hasher_key1);
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
 fn position_of_leftmost_one_bit(s: u64, max_width: u8) -> u8 {
     count_leading_zeros(s, max_width) + 1
 }
@@ -181,34 +498,210 @@ fn count_leading_zeros(mut s: u64, max_width: u8) -> u8 {
     lz
 }
 
+/// Encodes a `(register index, leftmost-one rank)` pair into a single
+/// sparse-list entry. The index occupies the high bits and the rank the low
+/// 8 bits, which is more than enough for the ranks this crate ever produces
+/// (at most `64 - b + 1 <= 61`).
+fn encode_sparse_entry(index: usize, rank: u8) -> u32 {
+    ((index as u32) << 8) | (rank as u32)
+}
+
+fn decode_sparse_entry(entry: u32) -> (usize, u8) {
+    ((entry >> 8) as usize, (entry & 0xFF) as u8)
+}
+
+fn sparse_index(entry: u32) -> usize {
+    (entry >> 8) as usize
+}
+
+/// Sorts `entries` and keeps only the maximum-rank entry per register index.
+fn merge_sparse_entries(entries: &mut Vec<u32>) {
+    entries.sort();
+    let mut merged = Vec::with_capacity(entries.len());
+    for &entry in entries.iter() {
+        let index = sparse_index(entry);
+        let replace_last = match merged.last() {
+            Some(&last) => sparse_index(last) == index,
+            None => false,
+        };
+        if replace_last {
+            let last = merged.len() - 1;
+            merged[last] = entry;
+        } else {
+            merged.push(entry);
+        }
+    }
+    *entries = merged;
+}
+
+/// Merges the buffered and already-sorted sparse entries without mutating
+/// either, for use by read-only paths such as `cardinality` and the histogram.
+fn effective_sparse_entries(sorted: &[u32], buffer: &[u32]) -> Vec<u32> {
+    let mut entries = Vec::with_capacity(sorted.len() + buffer.len());
+    entries.extend_from_slice(sorted);
+    entries.extend_from_slice(buffer);
+    merge_sparse_entries(&mut entries);
+    entries
+}
+
+/// Expands a sparse entry list into a dense `m`-length register array.
+fn dense_snapshot(m: usize, entries: &[u32]) -> PackedRegisters {
+    let mut registers = PackedRegisters::new(m);
+    for &entry in entries {
+        let (index, rank) = decode_sparse_entry(entry);
+        registers.set(index, rank);
+    }
+    registers
+}
+
 fn estimate_cardinality(hll: &HyperLogLog) -> (f64, Estimator) {
     let m_f64 = hll.m as f64;
-    let est = raw_hyperloglog_estimate(hll.alpha, m_f64, &hll.registers);
 
-    if est < (5.0 / 2.0 * m_f64) {
-        match count_zero_registers(&hll.registers) {
-            0 => (est, Estimator::HyperLogLog),
-            v => (linear_counting_estimate(m_f64, v as f64), Estimator::LinearCounting),
+    match hll.registers {
+        Registers::Dense(ref registers) => {
+            let raw = ertl_raw_estimate(hll.b, m_f64, registers);
+            estimate_from_raw(hll.b, m_f64, raw, registers)
+        }
+        Registers::Sparse { ref sorted, ref buffer } => {
+            let entries = effective_sparse_entries(sorted, buffer);
+            let zero_registers = hll.m - entries.len();
+
+            if zero_registers == 0 {
+                let registers = dense_snapshot(hll.m, &entries);
+                let raw = ertl_raw_estimate(hll.b, m_f64, &registers);
+                estimate_from_raw(hll.b, m_f64, raw, &registers)
+            } else {
+                (linear_counting_estimate(m_f64, zero_registers as f64), Estimator::LinearCounting)
+            }
+        }
+    }
+}
+
+/// Picks the final estimate from a raw estimate: below the per-precision
+/// `LINEAR_COUNTING_THRESHOLD`, linear counting wins; otherwise the raw
+/// estimate is used as-is. `ertl_raw_estimate` is already a near-unbiased
+/// maximum-likelihood estimator (unlike the classic `alpha*m^2/sum(2^-x)`
+/// formula HyperLogLog++'s bias-correction tables were built for), so no
+/// further bias correction is applied here.
+fn estimate_from_raw(b: u8, m: f64, raw: f64, registers: &PackedRegisters) -> (f64, Estimator) {
+    let threshold = LINEAR_COUNTING_THRESHOLD[(b - 4) as usize];
+
+    if raw < threshold {
+        match count_zero_registers(registers) {
+            0 => (raw, Estimator::HyperLogLog),
+            v => (linear_counting_estimate(m, v as f64), Estimator::LinearCounting),
         }
     } else {
-        (est, Estimator::HyperLogLog)
+        (raw, Estimator::HyperLogLog)
+    }
+}
+
+/// Per-precision raw-estimate threshold below which linear counting beats
+/// the raw estimator, indexed by `b - 4` (`b` from 4 to 16).
+const LINEAR_COUNTING_THRESHOLD: [f64; 13] = [
+    10.0, 20.0, 40.0, 80.0, 220.0, 400.0, 900.0, 1800.0, 3100.0, 6500.0, 11500.0, 20000.0, 50000.0,
+];
+
+fn count_zero_registers(registers: &PackedRegisters) -> usize {
+    registers.iter().filter(|&x| x == 0).count()
+}
+
+/// Ertl's improved raw estimator, built from the register-value multiplicity
+/// histogram instead of `alpha * m^2 / sum(2^-x)`. This is a maximum-likelihood-style
+/// estimate that is continuous across the whole cardinality range, which is why
+/// `estimate_from_raw` only still needs to pick between it and linear counting
+/// at very low cardinalities rather than across several switch points.
+fn ertl_raw_estimate(b: u8, m: f64, registers: &PackedRegisters) -> f64 {
+    let q = (64 - b) as usize;
+
+    let mut c = vec![0u64; q + 2];
+    for x in registers.iter() {
+        c[x as usize] += 1;
+    }
+
+    let mut z = m * tau((m - c[q + 1] as f64) / m);
+    for k in (1..=q).rev() {
+        z = 0.5 * (z + c[k] as f64);
     }
+    z += m * sigma(c[0] as f64 / m);
+
+    m * m / (2.0 * 2.0f64.ln() * z)
 }
 
-fn count_zero_registers(regsiters: &[u8]) -> usize {
-    regsiters.iter().filter(|&x| *x == 0).count()
+fn sigma(x: f64) -> f64 {
+    if x == 1.0 {
+        return std::f64::INFINITY;
+    }
+
+    let mut x = x;
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let z_old = z;
+        z += x * y;
+        y += y;
+        if z == z_old {
+            return z;
+        }
+    }
 }
 
-fn raw_hyperloglog_estimate(alpha: f64, m: f64, registers: &[u8]) -> f64 {
-    let sum = registers.iter()
-        .map(|&x| 2.0f64.powi(-(x as i32))).sum::<f64>();
-    alpha * m * m / sum
+fn tau(x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+
+    let mut x = x;
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let z_old = z;
+        y *= 0.5;
+        z -= (1.0 - x) * (1.0 - x) * y;
+        if z == z_old {
+            return z / 3.0;
+        }
+    }
 }
 
 fn linear_counting_estimate(m: f64, number_of_zero_registers: f64) -> f64 {
     m * (m / number_of_zero_registers).ln()
 }
 
+fn histogram_from_values<I: Iterator<Item = u8>>(values: I) -> String {
+    let mut histgram = Vec::new();
+
+    let mut map = BTreeMap::new();
+    for x in values {
+        let count = map.entry(x).or_insert(0);
+        *count += 1;
+    }
+
+    if let (Some(last_reg_value), Some(max_count)) = (map.keys().last(), map.values().max()) {
+        let width = 40.0;
+        let rate = width / (*max_count as f64);
+
+        for i in 0..(last_reg_value + 1) {
+            let mut line = format!("{:3}: ", i);
+
+            if let Some(count) = map.get(&i) {
+                let h_bar = std::iter::repeat("*")
+                    .take((*count as f64 * rate).ceil() as usize)
+                    .collect::<String>();
+                line.push_str(&h_bar);
+                line.push_str(&format!(" {}", count));
+            } else {
+                line.push_str("0");
+            };
+
+            histgram.push(line);
+        }
+    }
+    histgram.join("\n")
+}
+
 impl fmt::Debug for HyperLogLog {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let (est, est_method) = estimate_cardinality(self);
@@ -232,6 +725,129 @@ hasher: ({}, {})"#,
     }
 }
 
+/// A HyperLogLog sketch that can be fed from multiple threads without
+/// external locking. Registers are `AtomicU8`s updated with a lock-free
+/// "keep the max" compare-and-swap loop, so there is no sparse mode or
+/// bit-packing here: `insert` needs to address each register independently.
+pub struct ConcurrentHyperLogLog {
+    b: u8,
+    b_mask: usize,
+    m: usize,
+    /// Unused by `cardinality()` (see `HyperLogLog::alpha`); retained for
+    /// `Debug` output.
+    alpha: f64,
+    registers: Vec<AtomicU8>,
+    hasher_key0: u64,
+    hasher_key1: u64,
+}
+
+impl ConcurrentHyperLogLog {
+    pub fn new(b: u8) -> Result<Self, Box<Error>> {
+        if b < 4 || b > 16 {
+            return Err(From::from(format!("b must be between 4 and 16. b = {}", b)));
+        }
+
+        let m = 1 << b;
+        let alpha = get_alpha(b)?;
+
+        let mut rng = rand::OsRng::new()
+            .map_err(|e| format!("Failed to create and OS RNG: {}", e))?;
+
+        Ok(ConcurrentHyperLogLog {
+            b: b,
+            b_mask: m - 1,
+            m: m,
+            alpha: alpha,
+            registers: (0..m).map(|_| AtomicU8::new(0)).collect(),
+            hasher_key0: rng.gen(),
+            hasher_key1: rng.gen(),
+        })
+    }
+
+    pub fn insert<H: Hash>(&self, value: &H) {
+        let x = hash_value(value, self.hasher_key0, self.hasher_key1);
+        let j = x as usize & self.b_mask;
+        let w = x >> self.b;
+        let p1 = position_of_leftmost_one_bit(w, 64 - self.b);
+
+        let register = &self.registers[j];
+        let mut current = register.load(Ordering::Relaxed);
+        while current < p1 {
+            match register.compare_exchange_weak(current, p1, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(observed) => current = observed,
+            }
+        }
+    }
+
+    pub fn cardinality(&self) -> f64 {
+        let m_f64 = self.m as f64;
+        let registers = self.snapshot();
+        let raw = ertl_raw_estimate(self.b, m_f64, &registers);
+        estimate_from_raw(self.b, m_f64, raw, &registers).0
+    }
+
+    pub fn typical_error_rate(&self) -> f64 {
+        1.04 / (self.m as f64).sqrt()
+    }
+
+    pub fn histgram_of_register_value_distribution(&self) -> String {
+        histogram_from_values(self.registers.iter().map(|r| r.load(Ordering::Relaxed)))
+    }
+
+    pub fn merge(&mut self, other: &ConcurrentHyperLogLog) -> Result<(), Box<Error>> {
+        if self.b == other.b && self.m == other.m && self.hasher_key0 == other.hasher_key0 && self.hasher_key1 == other.hasher_key1 {
+            for (p1, p2) in self.registers.iter_mut().zip(other.registers.iter()) {
+                let other_value = p2.load(Ordering::Relaxed);
+                if *p1.get_mut() < other_value {
+                    *p1.get_mut() = other_value;
+                }
+            }
+            Ok(())
+        } else {
+            Err(From::from(format!("Specs does not match.\
+            b: {}|{}, m: {}|{}, hasher: ({},{})|({},{})",
+                                   self.b,
+                                   other.b,
+                                   self.m,
+                                   other.m,
+                                   self.hasher_key0,
+                                   self.hasher_key1,
+                                   other.hasher_key0,
+                                   other.hasher_key1,
+            )))
+        }
+    }
+
+    fn snapshot(&self) -> PackedRegisters {
+        let mut registers = PackedRegisters::new(self.m);
+        for (index, register) in self.registers.iter().enumerate() {
+            registers.set(index, register.load(Ordering::Relaxed));
+        }
+        registers
+    }
+}
+
+impl fmt::Debug for ConcurrentHyperLogLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               r#"ConcurrentHyperLogLog
+estimated cardinality: {}
+--------------------------------------------------------
+b:     {} bits (typical error rate: {}%)
+m:     {} registers
+alpha: {}
+hasher: ({}, {})"#,
+               self.cardinality(),
+               self.b,
+               self.typical_error_rate() * 100.0,
+               self.m,
+               self.alpha,
+               self.hasher_key0,
+               self.hasher_key1)
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -249,7 +865,10 @@ mod tests {
         assert_eq!(hll.b, 4);
         assert_eq!(hll.m, 2_usize.pow(4));
         assert_eq!(hll.alpha, 0.673);
-        assert_eq!(hll.registers.len(), 2_usize.pow(4));
+        assert!(match hll.registers {
+            Registers::Sparse { ref sorted, ref buffer } => sorted.is_empty() && buffer.is_empty(),
+            Registers::Dense(_) => false,
+        });
 
         assert!(HyperLogLog::new(16).is_ok());
     }
@@ -265,4 +884,106 @@ mod tests {
         }
         assert_eq!(hll.cardinality().round(), 3.0);
     }
+
+    #[test]
+    fn raw_estimate_is_unbiased_near_m() {
+        let b = 10;
+        let m = 2_usize.pow(b as u32);
+        let trials = 30;
+
+        let mut total_relerr = 0.0;
+        for trial in 0..trials {
+            let mut hll = HyperLogLog::new(b).unwrap();
+            for i in 0..m {
+                hll.insert(&(trial * m + i));
+            }
+            total_relerr += (hll.cardinality() - m as f64) / m as f64;
+        }
+
+        let mean_relerr = total_relerr / trials as f64;
+        let bound = 1.04 / (m as f64).sqrt() * 1.5;
+        assert!(mean_relerr.abs() < bound,
+                "mean relative error {} exceeded {} (n/m == 1.0 is exactly where a stale bias correction would show up)",
+                mean_relerr, bound);
+    }
+
+    #[test]
+    fn promotes_to_dense_past_the_sparse_threshold() {
+        let mut hll = HyperLogLog::new(4).unwrap();
+        for i in 0..10_000 {
+            hll.insert(&i);
+        }
+        assert!(match hll.registers {
+            Registers::Dense(_) => true,
+            Registers::Sparse { .. } => false,
+        });
+        assert!(hll.cardinality() > 0.0);
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let mut hll = HyperLogLog::new(8).unwrap();
+        for i in 0..5_000 {
+            hll.insert(&i);
+        }
+        assert!(match hll.registers {
+            Registers::Dense(_) => true,
+            Registers::Sparse { .. } => false,
+        });
+
+        let restored = HyperLogLog::from_bytes(&hll.to_bytes()).unwrap();
+        assert_eq!(restored.b, hll.b);
+        assert_eq!(restored.hasher_key0, hll.hasher_key0);
+        assert_eq!(restored.hasher_key1, hll.hasher_key1);
+        assert_eq!(restored.cardinality(), hll.cardinality());
+    }
+
+    #[test]
+    fn from_bytes_rejects_bad_magic() {
+        let hll = HyperLogLog::new(8).unwrap();
+        let mut bytes = hll.to_bytes();
+        bytes[0] = b'X';
+        assert!(HyperLogLog::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn packed_registers_round_trip_across_word_boundaries() {
+        let mut registers = PackedRegisters::new(64);
+        for i in 0..64 {
+            registers.set(i, (i % 61) as u8);
+        }
+        for i in 0..64 {
+            assert_eq!(registers.get(i), (i % 61) as u8);
+        }
+    }
+
+    #[test]
+    fn concurrent_insert_from_multiple_threads() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let hll = Arc::new(ConcurrentHyperLogLog::new(10).unwrap());
+        let handles: Vec<_> = (0..4).map(|t| {
+            let hll = Arc::clone(&hll);
+            thread::spawn(move || {
+                for i in 0..1_000 {
+                    hll.insert(&(t * 1_000 + i));
+                }
+            })
+        }).collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let relative_error = (hll.cardinality() - 4_000.0).abs() / 4_000.0;
+        assert!(relative_error < hll.typical_error_rate() * 5.0);
+    }
+
+    #[test]
+    fn concurrent_merge_rejects_mismatched_specs() {
+        let mut a = ConcurrentHyperLogLog::new(8).unwrap();
+        let b = ConcurrentHyperLogLog::new(9).unwrap();
+        assert!(a.merge(&b).is_err());
+    }
 }